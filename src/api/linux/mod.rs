@@ -0,0 +1,254 @@
+use std;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+use Sender;
+
+use gtk;
+use gtk::prelude::*;
+use libappindicator::{AppIndicator, AppIndicatorStatus};
+
+use SystrayError;
+use SystrayEvent;
+
+/// The indicator/menu built on the GTK thread, handed back to the caller
+/// once `Window::new` knows startup succeeded.
+struct WindowState {
+    indicator: AppIndicator,
+    menu: gtk::Menu,
+}
+
+unsafe impl Send for WindowState {}
+
+pub struct Window {
+    indicator: AppIndicator,
+    menu: gtk::Menu,
+    menu_items: HashMap<u32, gtk::MenuItem>,
+    radio_groups: HashMap<u32, Vec<gtk::RadioMenuItem>>,
+    /// GtkMenu backing each submenu created via `add_submenu_entry`, keyed
+    /// by the submenu's own index so nested items can look up their parent.
+    submenus: HashMap<u32, gtk::Menu>,
+    /// The GtkMenu each entry was actually appended to, keyed by its
+    /// index, so removing a nested item targets its real parent menu
+    /// instead of always assuming the top-level one.
+    item_menus: HashMap<u32, gtk::Menu>,
+    tx: Sender<SystrayEvent>,
+}
+
+unsafe impl Send for Window {}
+
+impl Window {
+    pub fn new(tx: Sender<SystrayEvent>) -> Result<Window, SystrayError> {
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let loop_tx = tx.clone();
+
+        // GTK is single-threaded: init, build the indicator/menu and pump
+        // gtk::main() all on the same dedicated thread, per lib.rs's
+        // documented contract that each backend owns its own OS loop.
+        thread::spawn(move || {
+            if let Err(e) = gtk::init() {
+                let _ = ready_tx.send(Err(SystrayError::OsError(format!("{}", e))));
+                return;
+            }
+
+            let mut indicator = AppIndicator::new("systray", "");
+            indicator.set_status(AppIndicatorStatus::APP_INDICATOR_STATUS_ACTIVE);
+
+            let menu = gtk::Menu::new();
+            indicator.set_menu(&mut menu.clone());
+
+            {
+                let click_tx = loop_tx.clone();
+                indicator.connect_activate(move |_| {
+                    let _ = click_tx.send(SystrayEvent::LeftClick);
+                });
+            }
+            {
+                let click_tx = loop_tx.clone();
+                indicator.connect_secondary_activate(move |_| {
+                    let _ = click_tx.send(SystrayEvent::RightClick);
+                });
+            }
+
+            let _ = ready_tx.send(Ok(WindowState {
+                indicator: indicator,
+                menu: menu,
+            }));
+
+            gtk::main();
+        });
+
+        match ready_rx.recv() {
+            Ok(Ok(state)) => Ok(Window {
+                indicator: state.indicator,
+                menu: state.menu,
+                menu_items: HashMap::new(),
+                radio_groups: HashMap::new(),
+                submenus: HashMap::new(),
+                item_menus: HashMap::new(),
+                tx: tx,
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(SystrayError::OsError("GTK thread exited before it finished starting up".into())),
+        }
+    }
+
+    pub fn quit(&self) {
+        gtk::main_quit();
+    }
+
+    pub fn set_tooltip(&self, tooltip: &String) -> Result<(), SystrayError> {
+        // AppIndicator doesn't support hover tooltips; use the label instead.
+        self.indicator.set_label(tooltip, "");
+        Ok(())
+    }
+
+    /// The GtkMenu that new entries under `parent` should be appended to:
+    /// the top-level menu when `parent` is `None`, otherwise the submenu
+    /// previously created for that index.
+    fn container(&self, parent: Option<u32>) -> Result<gtk::Menu, SystrayError> {
+        match parent {
+            None => Ok(self.menu.clone()),
+            Some(idx) => self.submenus.get(&idx).cloned().ok_or(SystrayError::NotImplementedError),
+        }
+    }
+
+    pub fn add_menu_separator(&mut self, parent: Option<u32>, _idx: u32) -> Result<(), SystrayError> {
+        let container = self.container(parent)?;
+        let item = gtk::SeparatorMenuItem::new();
+        container.append(&item);
+        container.show_all();
+        Ok(())
+    }
+
+    pub fn add_menu_entry(&mut self, parent: Option<u32>, idx: u32, item_name: &String) -> Result<(), SystrayError> {
+        let container = self.container(parent)?;
+        let item = gtk::MenuItem::new_with_label(item_name);
+        let tx = self.tx.clone();
+        item.connect_activate(move |_| {
+            let _ = tx.send(SystrayEvent::MenuItem { index: idx });
+        });
+
+        container.append(&item);
+        container.show_all();
+        self.menu_items.insert(idx, item);
+        self.item_menus.insert(idx, container);
+
+        Ok(())
+    }
+
+    pub fn add_submenu_entry(&mut self, parent: Option<u32>, idx: u32, label: &str) -> Result<(), SystrayError> {
+        let container = self.container(parent)?;
+
+        let item = gtk::MenuItem::new_with_label(label);
+        let submenu = gtk::Menu::new();
+        item.set_submenu(Some(&submenu));
+
+        container.append(&item);
+        container.show_all();
+        self.menu_items.insert(idx, item);
+        self.item_menus.insert(idx, container);
+        self.submenus.insert(idx, submenu);
+
+        Ok(())
+    }
+
+    pub fn add_checkable_menu_entry(&mut self, idx: u32, item_name: &String, checked: bool) -> Result<(), SystrayError> {
+        let item = gtk::CheckMenuItem::new_with_label(item_name);
+        item.set_active(checked);
+
+        let tx = self.tx.clone();
+        item.connect_activate(move |_| {
+            let _ = tx.send(SystrayEvent::MenuItem { index: idx });
+        });
+
+        self.menu.append(&item);
+        self.menu.show_all();
+        self.menu_items.insert(idx, item.upcast());
+        self.item_menus.insert(idx, self.menu.clone());
+
+        Ok(())
+    }
+
+    pub fn add_radio_menu_entry(&mut self, idx: u32, group_id: u32, item_name: &String, checked: bool) -> Result<(), SystrayError> {
+        let group = self.radio_groups.entry(group_id).or_insert_with(Vec::new);
+        let item = match group.first() {
+            Some(leader) => gtk::RadioMenuItem::new_with_label_from_widget(leader, item_name),
+            None => gtk::RadioMenuItem::new_with_label(item_name),
+        };
+        item.set_active(checked);
+        group.push(item.clone());
+
+        let tx = self.tx.clone();
+        item.connect_activate(move |_| {
+            let _ = tx.send(SystrayEvent::MenuItem { index: idx });
+        });
+
+        self.menu.append(&item);
+        self.menu.show_all();
+        self.menu_items.insert(idx, item.upcast());
+        self.item_menus.insert(idx, self.menu.clone());
+
+        Ok(())
+    }
+
+    pub fn set_menu_item_checked(&mut self, idx: u32, checked: bool) -> Result<(), SystrayError> {
+        match self.menu_items.get(&idx) {
+            Some(item) => {
+                if let Some(check_item) = item.clone().downcast::<gtk::CheckMenuItem>().ok() {
+                    check_item.set_active(checked);
+                    Ok(())
+                } else {
+                    Err(SystrayError::NotImplementedError)
+                }
+            }
+            None => Err(SystrayError::NotImplementedError),
+        }
+    }
+
+    pub fn remove_menu_entry(&mut self, idx: u32, _item_name: &String) -> Result<(), SystrayError> {
+        match self.menu_items.remove(&idx) {
+            Some(item) => {
+                let container = self.item_menus.remove(&idx).unwrap_or_else(|| self.menu.clone());
+                container.remove(&item);
+                Ok(())
+            }
+            None => Err(SystrayError::NotImplementedError),
+        }
+    }
+
+    pub fn set_menu_item_label(&mut self, idx: u32, label: &str) -> Result<(), SystrayError> {
+        match self.menu_items.get(&idx) {
+            Some(item) => {
+                item.set_label(label);
+                Ok(())
+            }
+            None => Err(SystrayError::NotImplementedError),
+        }
+    }
+
+    pub fn set_menu_item_enabled(&mut self, idx: u32, enabled: bool) -> Result<(), SystrayError> {
+        match self.menu_items.get(&idx) {
+            Some(item) => {
+                item.set_sensitive(enabled);
+                Ok(())
+            }
+            None => Err(SystrayError::NotImplementedError),
+        }
+    }
+
+    pub fn set_icon_from_file(&self, file: &String) -> Result<(), SystrayError> {
+        self.indicator.set_icon(file);
+        Ok(())
+    }
+
+    pub fn set_icon_from_resource(&self, resource: &String) -> Result<(), SystrayError> {
+        self.indicator.set_icon(resource);
+        Ok(())
+    }
+
+    pub fn shutdown(&self) -> Result<(), SystrayError> {
+        self.quit();
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+#[cfg(target_os = "windows")]
+pub mod win32;
+#[cfg(target_os = "windows")]
+pub use self::win32 as api;
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(target_os = "linux")]
+pub use self::linux as api;
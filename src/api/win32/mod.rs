@@ -0,0 +1,488 @@
+use std;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::iter::once;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::mpsc;
+use Sender;
+use std::sync::Mutex;
+use std::thread;
+
+use kernel32;
+use libc;
+use shell32;
+use user32;
+use winapi;
+
+use SystrayError;
+use SystrayEvent;
+
+// Sent to our own window right after creation, once the message loop is
+// pumping, so the notify icon is only added from the thread that owns it.
+const WM_USER_CREATE: u32 = winapi::WM_USER + 1;
+const WM_USER_ICON: u32 = winapi::WM_USER + 2;
+const NOTIFY_ICON_ID: u32 = 1;
+
+lazy_static! {
+    static ref WININFO_STASH: Mutex<Option<WindowsLoopData>> = Mutex::new(None);
+}
+
+struct WindowsLoopData {
+    pub hwnd: winapi::HWND,
+    pub info: WindowInfo,
+}
+
+#[derive(Clone)]
+struct WindowInfo {
+    pub hwnd: winapi::HWND,
+    pub hmenu: winapi::HMENU,
+    /// The HMENU each menu item (including submenu headers) was inserted
+    /// into, keyed by its index, so later mutation/removal targets the
+    /// item's real owning menu instead of always assuming the root one.
+    pub himenu_items: HashMap<u32, winapi::HMENU>,
+    pub radio_groups: HashMap<u32, Vec<u32>>,
+    /// Popup HMENU backing each submenu created via `add_submenu_entry`,
+    /// keyed by the submenu's own index.
+    pub submenus: HashMap<u32, winapi::HMENU>,
+    pub hicon: winapi::HICON,
+    pub events: Sender<SystrayEvent>,
+    pub info_tooltip: [u16; 128],
+}
+
+unsafe impl Send for WindowInfo {}
+
+fn to_wstring(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(once(0)).collect()
+}
+
+/// Build the `NOTIFYICONDATAW` describing our single notify icon, shared
+/// by the `NIM_ADD`/`NIM_MODIFY`/`NIM_DELETE` call sites.
+unsafe fn notify_icon_data(info: &WindowInfo) -> winapi::NOTIFYICONDATAW {
+    let mut nid = mem::zeroed::<winapi::NOTIFYICONDATAW>();
+    nid.cbSize = mem::size_of::<winapi::NOTIFYICONDATAW>() as u32;
+    nid.hWnd = info.hwnd;
+    nid.uID = NOTIFY_ICON_ID;
+    nid.uFlags = winapi::NIF_MESSAGE | winapi::NIF_ICON | winapi::NIF_TIP;
+    nid.uCallbackMessage = WM_USER_ICON;
+    nid.hIcon = info.hicon;
+    nid.szTip = info.info_tooltip;
+    nid
+}
+
+/// `WM_MENUCOMMAND`/`GetMenuItemID(hmenu, pos)` only fire for a menu
+/// created with this style; without it, a popped menu reports selections
+/// through `WM_COMMAND` instead, which we never handle.
+unsafe fn enable_notify_by_position(hmenu: winapi::HMENU) {
+    let mut info = mem::zeroed::<winapi::MENUINFO>();
+    info.cbSize = mem::size_of::<winapi::MENUINFO>() as u32;
+    info.fMask = winapi::MIM_STYLE;
+    info.dwStyle = winapi::MNS_NOTIFYBYPOS;
+    user32::SetMenuInfo(hmenu, &info);
+}
+
+/// `Shell_NotifyIcon` has no notion of an attached menu — unlike
+/// `libappindicator` on Linux, Windows never pops the tray menu on its
+/// own, so we have to do it ourselves in response to the click.
+unsafe fn show_popup_menu(hwnd: winapi::HWND, hmenu: winapi::HMENU) {
+    let mut pos = mem::zeroed::<winapi::POINT>();
+    user32::GetCursorPos(&mut pos);
+
+    // SetForegroundWindow + the WM_NULL nudge afterwards are the
+    // documented workaround for TrackPopupMenu not dismissing the menu
+    // when the user clicks away from it.
+    user32::SetForegroundWindow(hwnd);
+    user32::TrackPopupMenu(hmenu, winapi::TPM_RIGHTBUTTON, pos.x, pos.y, 0, hwnd, ptr::null_mut());
+    user32::PostMessageW(hwnd, winapi::WM_NULL, 0, 0);
+}
+
+unsafe extern "system" fn window_proc(
+    h_wnd: winapi::HWND,
+    msg: winapi::UINT,
+    w_param: winapi::WPARAM,
+    l_param: winapi::LPARAM,
+) -> winapi::LRESULT {
+    if msg == WM_USER_CREATE {
+        if let Some(ref stash) = *WININFO_STASH.lock().unwrap() {
+            let mut nid = notify_icon_data(&stash.info);
+            shell32::Shell_NotifyIconW(winapi::NIM_ADD, &mut nid);
+        }
+    } else if msg == winapi::WM_MENUCOMMAND {
+        let menu_id = user32::GetMenuItemID(l_param as winapi::HMENU, w_param as i32) as u32;
+        if let Some(ref stash) = *WININFO_STASH.lock().unwrap() {
+            let _ = stash.info.events.send(SystrayEvent::MenuItem { index: menu_id });
+        }
+    } else if msg == WM_USER_ICON {
+        // lParam carries the mouse message that was sent to the notify
+        // icon (WM_LBUTTONUP, WM_RBUTTONUP, WM_LBUTTONDBLCLK, ...).
+        let event = match l_param as u32 {
+            winapi::WM_LBUTTONUP => Some(SystrayEvent::LeftClick),
+            winapi::WM_RBUTTONUP => Some(SystrayEvent::RightClick),
+            winapi::WM_LBUTTONDBLCLK => Some(SystrayEvent::DoubleClick),
+            _ => None,
+        };
+
+        if let Some(event) = event {
+            if let Some(ref stash) = *WININFO_STASH.lock().unwrap() {
+                let _ = stash.info.events.send(event);
+            }
+        }
+
+        if l_param as u32 == winapi::WM_RBUTTONUP || l_param as u32 == winapi::WM_LBUTTONUP {
+            if let Some(ref stash) = *WININFO_STASH.lock().unwrap() {
+                show_popup_menu(h_wnd, stash.info.hmenu);
+            }
+        }
+    }
+
+    user32::DefWindowProcW(h_wnd, msg, w_param, l_param)
+}
+
+pub struct Window {
+    info: WindowInfo,
+}
+
+unsafe impl Send for Window {}
+
+impl Window {
+    pub fn new(tx: Sender<SystrayEvent>) -> Result<Window, SystrayError> {
+        // The window, its menu and the notify icon all have to live on
+        // the thread that pumps their messages, so build everything on a
+        // dedicated thread and hand the result back over a channel.
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        thread::spawn(move || unsafe {
+            let class_name = to_wstring("systray-rs");
+            let hinstance = kernel32::GetModuleHandleW(ptr::null_mut());
+            let wnd_class = winapi::WNDCLASSW {
+                style: 0,
+                lpfnWndProc: Some(window_proc),
+                cbClsExtra: 0,
+                cbWndExtra: 0,
+                hInstance: hinstance,
+                hIcon: ptr::null_mut(),
+                hCursor: ptr::null_mut(),
+                hbrBackground: ptr::null_mut(),
+                lpszMenuName: ptr::null_mut(),
+                lpszClassName: class_name.as_ptr(),
+            };
+
+            user32::RegisterClassW(&wnd_class);
+
+            let hwnd = user32::CreateWindowExW(
+                0,
+                class_name.as_ptr(),
+                to_wstring("systray-rs").as_ptr(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                hinstance,
+                ptr::null_mut(),
+            );
+
+            if hwnd.is_null() {
+                let _ = ready_tx.send(Err(SystrayError::OsError("Failed to create window".into())));
+                return;
+            }
+
+            let hmenu = user32::CreatePopupMenu();
+            enable_notify_by_position(hmenu);
+            let mut info_tooltip = [0u16; 128];
+            let tip = to_wstring("systray-rs");
+            info_tooltip[..tip.len()].copy_from_slice(&tip);
+
+            let info = WindowInfo {
+                hwnd: hwnd,
+                hmenu: hmenu,
+                himenu_items: HashMap::new(),
+                radio_groups: HashMap::new(),
+                submenus: HashMap::new(),
+                hicon: ptr::null_mut(),
+                events: tx,
+                info_tooltip: info_tooltip,
+            };
+
+            *WININFO_STASH.lock().unwrap() = Some(WindowsLoopData {
+                hwnd: hwnd,
+                info: info.clone(),
+            });
+
+            // Deferred to a posted message, rather than called inline
+            // here, so the notify icon is only added once this thread is
+            // actually pumping messages for it.
+            user32::PostMessageW(hwnd, WM_USER_CREATE, 0, 0);
+
+            let _ = ready_tx.send(Ok(info));
+
+            let mut msg = mem::zeroed::<winapi::MSG>();
+            loop {
+                let ret = user32::GetMessageW(&mut msg, ptr::null_mut(), 0, 0);
+                if ret == 0 || ret == -1 {
+                    break;
+                }
+                user32::TranslateMessage(&msg);
+                user32::DispatchMessageW(&msg);
+            }
+        });
+
+        match ready_rx.recv() {
+            Ok(Ok(info)) => Ok(Window { info: info }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(SystrayError::OsError("Window thread exited before it finished starting up".into())),
+        }
+    }
+
+    pub fn quit(&self) {
+        unsafe {
+            user32::PostMessageW(self.info.hwnd, winapi::WM_CLOSE, 0, 0);
+        }
+    }
+
+    pub fn set_tooltip(&self, tooltip: &String) -> Result<(), SystrayError> {
+        let mut info_tooltip = [0u16; 128];
+        let tip = to_wstring(tooltip);
+        let len = tip.len().min(info_tooltip.len());
+        info_tooltip[..len].copy_from_slice(&tip[..len]);
+
+        unsafe {
+            let mut nid = notify_icon_data(&self.info);
+            nid.uFlags = winapi::NIF_TIP;
+            nid.szTip = info_tooltip;
+            if shell32::Shell_NotifyIconW(winapi::NIM_MODIFY, &mut nid) == 0 {
+                return Err(SystrayError::OsError("Failed to set tooltip".into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The HMENU that new entries under `parent` should be appended to:
+    /// the root tray menu when `parent` is `None`, otherwise the popup
+    /// menu previously created for that submenu index.
+    fn container(&self, parent: Option<u32>) -> Result<winapi::HMENU, SystrayError> {
+        match parent {
+            None => Ok(self.info.hmenu),
+            Some(idx) => self.info.submenus.get(&idx).cloned()
+                .ok_or(SystrayError::NotImplementedError),
+        }
+    }
+
+    /// The HMENU that `idx` actually lives in, as recorded when it was
+    /// inserted. Falls back to the root menu for an index with no such
+    /// record.
+    fn owning_menu(&self, idx: u32) -> winapi::HMENU {
+        self.info.himenu_items.get(&idx).cloned().unwrap_or(self.info.hmenu)
+    }
+
+    pub fn add_menu_separator(&mut self, parent: Option<u32>, _idx: u32) -> Result<(), SystrayError> {
+        let hmenu = self.container(parent)?;
+        unsafe {
+            let mut item = mem::zeroed::<winapi::MENUITEMINFOW>();
+            item.cbSize = mem::size_of::<winapi::MENUITEMINFOW>() as u32;
+            item.fMask = winapi::MIIM_FTYPE;
+            item.fType = winapi::MFT_SEPARATOR;
+            user32::InsertMenuItemW(hmenu, 0xFFFFFFFF, 1, &item);
+        }
+        Ok(())
+    }
+
+    pub fn add_menu_entry(&mut self, parent: Option<u32>, idx: u32, item_name: &String) -> Result<(), SystrayError> {
+        let hmenu = self.container(parent)?;
+        let mut label = to_wstring(item_name);
+        unsafe {
+            let mut item = mem::zeroed::<winapi::MENUITEMINFOW>();
+            item.cbSize = mem::size_of::<winapi::MENUITEMINFOW>() as u32;
+            item.fMask = winapi::MIIM_FTYPE | winapi::MIIM_STRING | winapi::MIIM_ID;
+            item.fType = winapi::MFT_STRING;
+            item.wID = idx;
+            item.dwTypeData = label.as_mut_ptr();
+            item.cch = (label.len() - 1) as u32;
+            user32::InsertMenuItemW(hmenu, 0xFFFFFFFF, 1, &item);
+        }
+        self.info.himenu_items.insert(idx, hmenu);
+        Ok(())
+    }
+
+    pub fn add_submenu_entry(&mut self, parent: Option<u32>, idx: u32, label: &str) -> Result<(), SystrayError> {
+        let hmenu = self.container(parent)?;
+        let mut wide_label = to_wstring(label);
+        unsafe {
+            let submenu = user32::CreatePopupMenu();
+            enable_notify_by_position(submenu);
+
+            let mut item = mem::zeroed::<winapi::MENUITEMINFOW>();
+            item.cbSize = mem::size_of::<winapi::MENUITEMINFOW>() as u32;
+            item.fMask = winapi::MIIM_FTYPE | winapi::MIIM_STRING | winapi::MIIM_SUBMENU | winapi::MIIM_ID;
+            item.fType = winapi::MFT_STRING;
+            item.wID = idx;
+            item.hSubMenu = submenu;
+            item.dwTypeData = wide_label.as_mut_ptr();
+            item.cch = (wide_label.len() - 1) as u32;
+            user32::InsertMenuItemW(hmenu, 0xFFFFFFFF, 1, &item);
+
+            self.info.submenus.insert(idx, submenu);
+        }
+        self.info.himenu_items.insert(idx, hmenu);
+        Ok(())
+    }
+
+    pub fn remove_menu_entry(&mut self, idx: u32, _item_name: &String) -> Result<(), SystrayError> {
+        let hmenu = self.owning_menu(idx);
+        unsafe {
+            if user32::RemoveMenu(hmenu, idx, winapi::MF_BYCOMMAND) == 0 {
+                return Err(SystrayError::OsError("Failed to remove menu item".into()));
+            }
+        }
+        self.info.himenu_items.remove(&idx);
+
+        // RemoveMenu only detaches a submenu header from its parent; it
+        // doesn't destroy the popup HMENU it owned. Do that ourselves, or
+        // it leaks for the process's lifetime and a stale entry in
+        // `submenus` would let a later add_menu_entry(Some(idx), ..) keep
+        // inserting into a popup nothing can show any more.
+        if let Some(submenu) = self.info.submenus.remove(&idx) {
+            unsafe {
+                user32::DestroyMenu(submenu);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add_checkable_menu_entry(&mut self, idx: u32, item_name: &String, checked: bool) -> Result<(), SystrayError> {
+        self.add_menu_entry(None, idx, item_name)?;
+        self.set_menu_item_checked(idx, checked)
+    }
+
+    pub fn add_radio_menu_entry(&mut self, idx: u32, group_id: u32, item_name: &String, checked: bool) -> Result<(), SystrayError> {
+        let hmenu = self.container(None)?;
+        let mut label = to_wstring(item_name);
+        unsafe {
+            let mut item = mem::zeroed::<winapi::MENUITEMINFOW>();
+            item.cbSize = mem::size_of::<winapi::MENUITEMINFOW>() as u32;
+            item.fMask = winapi::MIIM_FTYPE | winapi::MIIM_STRING | winapi::MIIM_ID;
+            item.fType = winapi::MFT_STRING | winapi::MFT_RADIOCHECK;
+            item.wID = idx;
+            item.dwTypeData = label.as_mut_ptr();
+            item.cch = (label.len() - 1) as u32;
+            user32::InsertMenuItemW(hmenu, 0xFFFFFFFF, 1, &item);
+        }
+        self.info.himenu_items.insert(idx, hmenu);
+        self.info.radio_groups.entry(group_id).or_insert_with(Vec::new).push(idx);
+        self.set_menu_item_checked(idx, checked)
+    }
+
+    pub fn set_menu_item_checked(&mut self, idx: u32, checked: bool) -> Result<(), SystrayError> {
+        let group = self.info.radio_groups.values().find(|members| members.contains(&idx)).cloned();
+        match group {
+            // CheckMenuRadioItem's [idFirst, idLast] is a *command id*
+            // range, not a group membership list — item ids come from
+            // Application's single shared counter, so they're rarely
+            // contiguous once anything else has been added to the menu,
+            // and using the range would silently uncheck unrelated items
+            // that happen to fall inside it. Drive every group member's
+            // check state individually instead; MFT_RADIOCHECK (set when
+            // the item was created) is what makes CheckMenuItem/MF_CHECKED
+            // draw the radio bullet rather than a checkmark.
+            Some(members) if checked => {
+                for member in members {
+                    let member_hmenu = self.owning_menu(member);
+                    let state = if member == idx { winapi::MF_CHECKED } else { winapi::MF_UNCHECKED };
+                    unsafe {
+                        user32::CheckMenuItem(member_hmenu, member, winapi::MF_BYCOMMAND | state);
+                    }
+                }
+            }
+            _ => {
+                let hmenu = self.owning_menu(idx);
+                let state = if checked { winapi::MF_CHECKED } else { winapi::MF_UNCHECKED };
+                unsafe {
+                    user32::CheckMenuItem(hmenu, idx, winapi::MF_BYCOMMAND | state);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_label(&mut self, idx: u32, label: &str) -> Result<(), SystrayError> {
+        let hmenu = self.owning_menu(idx);
+        let mut wide_label = to_wstring(label);
+        unsafe {
+            let mut item = mem::zeroed::<winapi::MENUITEMINFOW>();
+            item.cbSize = mem::size_of::<winapi::MENUITEMINFOW>() as u32;
+            item.fMask = winapi::MIIM_STRING;
+            item.dwTypeData = wide_label.as_mut_ptr();
+            item.cch = (wide_label.len() - 1) as u32;
+            if user32::SetMenuItemInfoW(hmenu, idx, winapi::FALSE, &item) == 0 {
+                return Err(SystrayError::OsError("Failed to relabel menu item".into()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_enabled(&mut self, idx: u32, enabled: bool) -> Result<(), SystrayError> {
+        let hmenu = self.owning_menu(idx);
+        unsafe {
+            let state = if enabled { winapi::MF_ENABLED } else { winapi::MF_GRAYED };
+            user32::EnableMenuItem(hmenu, idx, winapi::MF_BYCOMMAND | state);
+        }
+        Ok(())
+    }
+
+    fn set_hicon(&self, hicon: winapi::HICON) -> Result<(), SystrayError> {
+        unsafe {
+            let mut nid = notify_icon_data(&self.info);
+            nid.uFlags = winapi::NIF_ICON;
+            nid.hIcon = hicon;
+            if shell32::Shell_NotifyIconW(winapi::NIM_MODIFY, &mut nid) == 0 {
+                return Err(SystrayError::OsError("Failed to set tray icon".into()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_icon_from_file(&self, file: &String) -> Result<(), SystrayError> {
+        let wide_path = to_wstring(file);
+        let hicon = unsafe {
+            user32::LoadImageW(
+                ptr::null_mut(),
+                wide_path.as_ptr(),
+                winapi::IMAGE_ICON,
+                0,
+                0,
+                winapi::LR_LOADFROMFILE | winapi::LR_DEFAULTSIZE,
+            ) as winapi::HICON
+        };
+
+        if hicon.is_null() {
+            return Err(SystrayError::OsError(format!("Failed to load icon from {}", file)));
+        }
+
+        self.set_hicon(hicon)
+    }
+
+    pub fn set_icon_from_resource(&self, resource: &String) -> Result<(), SystrayError> {
+        let wide_resource = to_wstring(resource);
+        let hinstance = unsafe { kernel32::GetModuleHandleW(ptr::null_mut()) };
+        let hicon = unsafe { user32::LoadIconW(hinstance, wide_resource.as_ptr()) };
+
+        if hicon.is_null() {
+            return Err(SystrayError::OsError(format!("Failed to load icon resource {}", resource)));
+        }
+
+        self.set_hicon(hicon)
+    }
+
+    pub fn shutdown(&self) -> Result<(), SystrayError> {
+        unsafe {
+            let mut nid = notify_icon_data(&self.info);
+            shell32::Shell_NotifyIconW(winapi::NIM_DELETE, &mut nid);
+        }
+        self.quit();
+        Ok(())
+    }
+}
@@ -9,28 +9,62 @@ extern crate kernel32;
 #[cfg(target_os = "windows")]
 extern crate user32;
 #[cfg(target_os = "windows")]
+extern crate shell32;
+#[cfg(target_os = "windows")]
 extern crate libc;
+#[cfg(target_os = "windows")]
+#[macro_use]
+extern crate lazy_static;
 #[cfg(target_os = "linux")]
 extern crate gtk;
 #[cfg(target_os = "linux")]
 extern crate glib;
 #[cfg(target_os = "linux")]
 extern crate libappindicator;
+#[cfg(feature = "async")]
+extern crate tokio;
 
 pub mod api;
 
 use std::collections::HashMap;
-use std::sync::mpsc::{channel, Receiver};
 
-#[derive(Clone, Debug)]
+// With the default feature set, the OS-loop thread hands events to
+// `Application` over a plain blocking `std::sync::mpsc` channel. With the
+// `async` feature, the same `Sender`/`Receiver` names resolve to an
+// unbounded tokio channel instead, so platform backends don't need to
+// care which mode they're built with, and callers can `.await` events
+// via `Application::wait_for_message`/`wait_for_message_callback` instead
+// of blocking.
+#[cfg(not(feature = "async"))]
+pub use std::sync::mpsc::{channel, Receiver, Sender};
+#[cfg(feature = "async")]
+pub use tokio::sync::mpsc::{unbounded_channel as channel, UnboundedReceiver as Receiver, UnboundedSender as Sender};
+
+/// A boxed error type used by `SystrayError::Error`, letting platform
+/// backends propagate their native errors losslessly instead of
+/// stringifying everything into `OsError(String)`.
+pub type BoxedError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+// Note: earlier versions derived `Clone` here too. `Error(BoxedError)`
+// can't be `Clone` (`Box<dyn Error>` isn't), so that impl had to be
+// dropped — a breaking change for any caller that relied on
+// `SystrayError: Clone`.
+#[derive(Debug)]
 pub enum SystrayError {
     OsError(String),
     NotImplementedError,
     UnknownError,
+    Error(BoxedError),
 }
 
-pub struct SystrayEvent {
-    menu_index: u32,
+/// An event originating from the platform's OS loop: either a menu item
+/// being selected, or a click/double-click on the tray icon itself.
+#[derive(Clone, Copy)]
+pub enum SystrayEvent {
+    MenuItem { index: u32 },
+    LeftClick,
+    RightClick,
+    DoubleClick,
 }
 
 impl std::fmt::Display for SystrayError {
@@ -39,10 +73,26 @@ impl std::fmt::Display for SystrayError {
             &SystrayError::OsError(ref err_str) => write!(f, "OsError: {}", err_str),
             &SystrayError::NotImplementedError => write!(f, "Functionality is not implemented yet"),
             &SystrayError::UnknownError => write!(f, "Unknown error occurrred"),
+            &SystrayError::Error(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SystrayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            &SystrayError::Error(ref err) => Some(err.as_ref()),
+            _ => None,
         }
     }
 }
 
+impl From<BoxedError> for SystrayError {
+    fn from(err: BoxedError) -> SystrayError {
+        SystrayError::Error(err)
+    }
+}
+
 pub struct Application {
     window: api::api::Window,
     menu_idx: u32,
@@ -53,12 +103,46 @@ pub struct Application {
     items: HashMap<String, u32>,
     items_reversed: HashMap<u32, String>,
 
+    /// Checked state of checkable and radio menu items, keyed by index.
+    checked: HashMap<u32, bool>,
+    /// Radio group membership: maps an item's index to its group id.
+    /// Items absent from this map are plain checkable (or unchecked) items.
+    radio_groups: HashMap<u32, u32>,
+
+    /// Callbacks fired when the tray icon itself (not a menu item) is
+    /// clicked or double-clicked.
+    on_click: Option<Callback>,
+    on_double_click: Option<Callback>,
+
     // Each platform-specific window module will set up its own thread for
     // dealing with the OS main loop. Use this channel for receiving events from
     // that thread.
     rx: Receiver<SystrayEvent>,
 }
 
+/// A handle to a submenu created with `Application::add_submenu` (or
+/// `SubMenu::add_submenu`, for nesting). Use it to add items, separators,
+/// and further submenus underneath that submenu instead of the top level
+/// of the tray menu.
+pub struct SubMenu {
+    idx: u32,
+}
+
+impl SubMenu {
+    pub fn add_menu_item<F>(&self, app: &mut Application, item_name: &String, f: F) -> Result<u32, SystrayError>
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        app.add_menu_item_in(Some(self.idx), item_name, f)
+    }
+
+    pub fn add_menu_separator(&self, app: &mut Application) -> Result<u32, SystrayError> {
+        app.add_menu_separator_in(Some(self.idx))
+    }
+
+    pub fn add_submenu(&self, app: &mut Application, label: &str) -> Result<SubMenu, SystrayError> {
+        app.add_submenu_in(Some(self.idx), label)
+    }
+}
+
 type Callback = Box<(Fn(&mut Application) -> () + 'static)>;
 
 fn make_callback<F>(f: F) -> Callback
@@ -66,6 +150,20 @@ fn make_callback<F>(f: F) -> Callback
     Box::new(f) as Callback
 }
 
+/// Every member of `idx`'s radio group (including `idx` itself), or an
+/// empty vec if `idx` isn't in a radio group at all. Pulled out of
+/// `Application::clear_radio_siblings` so the group-membership logic can
+/// be exercised without a live backend `Window`.
+fn radio_group_members(radio_groups: &HashMap<u32, u32>, idx: u32) -> Vec<u32> {
+    match radio_groups.get(&idx) {
+        Some(&group_id) => radio_groups.iter()
+            .filter(|&(_, &g)| g == group_id)
+            .map(|(&i, _)| i)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
 impl Application {
     pub fn new() -> Result<Application, SystrayError> {
         let (event_tx, event_rx) = channel();
@@ -76,6 +174,10 @@ impl Application {
                 callback: HashMap::new(),
                 items: HashMap::new(),
                 items_reversed: HashMap::new(),
+                checked: HashMap::new(),
+                radio_groups: HashMap::new(),
+                on_click: None,
+                on_double_click: None,
                 rx: event_rx
             }),
             Err(e) => Err(e)
@@ -83,21 +185,95 @@ impl Application {
     }
 
     pub fn add_menu_item<F>(&mut self, item_name: &String, f: F) -> Result<u32, SystrayError>
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        self.add_menu_item_in(None, item_name, f)
+    }
+
+    /// Shared implementation behind `add_menu_item` and `SubMenu::add_menu_item`.
+    /// `parent` is `None` for the top-level menu, or `Some(submenu_idx)` to
+    /// nest the item under a previously created submenu.
+    fn add_menu_item_in<F>(&mut self, parent: Option<u32>, item_name: &String, f: F) -> Result<u32, SystrayError>
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        let idx = self.menu_idx;
+        if let Err(e) = self.window.add_menu_entry(parent, idx, item_name) {
+            return Err(e);
+        }
+
+        self.items.insert(item_name.clone().to_string(), idx);
+        self.items_reversed.insert(idx, item_name.clone());
+        self.callback.insert(idx, make_callback(f));
+        self.menu_idx += 1;
+
+        Ok(idx)
+    }
+
+    pub fn add_checkable_menu_item<F>(&mut self, item_name: &String, checked: bool, f: F) -> Result<u32, SystrayError>
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        let idx = self.menu_idx;
+        if let Err(e) = self.window.add_checkable_menu_entry(idx, item_name, checked) {
+            return Err(e);
+        }
+
+        self.items.insert(item_name.clone().to_string(), idx);
+        self.items_reversed.insert(idx, item_name.clone());
+        self.callback.insert(idx, make_callback(f));
+        self.checked.insert(idx, checked);
+        self.menu_idx += 1;
+
+        Ok(idx)
+    }
+
+    pub fn add_radio_menu_item<F>(&mut self, group_id: u32, item_name: &String, checked: bool, f: F) -> Result<u32, SystrayError>
         where F: std::ops::Fn(&mut Application) -> () + 'static {
         let idx = self.menu_idx;
-        if let Err(e) = self.window.add_menu_entry(idx, item_name) {
+        if let Err(e) = self.window.add_radio_menu_entry(idx, group_id, item_name, checked) {
             return Err(e);
         }
 
         self.items.insert(item_name.clone().to_string(), idx);
         self.items_reversed.insert(idx, item_name.clone());
         self.callback.insert(idx, make_callback(f));
+        self.checked.insert(idx, checked);
+        self.radio_groups.insert(idx, group_id);
         self.menu_idx += 1;
 
         Ok(idx)
     }
 
-    #[cfg(windows)]
+    /// Register a callback fired when the user left-clicks the tray icon.
+    pub fn on_click<F>(&mut self, f: F)
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        self.on_click = Some(make_callback(f));
+    }
+
+    /// Register a callback fired when the user double-clicks the tray icon.
+    pub fn on_double_click<F>(&mut self, f: F)
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        self.on_double_click = Some(make_callback(f));
+    }
+
+    pub fn set_menu_item_checked(&mut self, idx: u32, checked: bool) -> Result<(), SystrayError> {
+        if let Err(e) = self.window.set_menu_item_checked(idx, checked) {
+            return Err(e);
+        }
+        self.checked.insert(idx, checked);
+        Ok(())
+    }
+
+    pub fn is_menu_item_checked(&self, idx: u32) -> bool {
+        *self.checked.get(&idx).unwrap_or(&false)
+    }
+
+    /// If `idx` belongs to a radio group, check it and clear every other
+    /// member of that group, both in our own state and on the backend.
+    fn clear_radio_siblings(&mut self, idx: u32) {
+        for sibling in radio_group_members(&self.radio_groups, idx) {
+            let checked = sibling == idx;
+            self.checked.insert(sibling, checked);
+            let _ = self.window.set_menu_item_checked(sibling, checked);
+        }
+    }
+
     pub fn remove_menu_item(&mut self, item_name: &String) -> Result<(), SystrayError> {
         match self.items.get(item_name) {
             Some(idx) => {
@@ -108,6 +284,9 @@ impl Application {
                 // We got the item, so we know we can remove it
                 let idx = self.items.remove(item_name).expect("Failed to remove item from HashSet");
                 self.items_reversed.remove(&idx).expect("Failed to remove item from reversed HashSet");
+                self.callback.remove(&idx);
+                self.checked.remove(&idx);
+                self.radio_groups.remove(&idx);
 
                 Ok(())
             },
@@ -115,15 +294,59 @@ impl Application {
         }
     }
 
+    /// Change the label of an already-created menu item, keeping the
+    /// `items`/`items_reversed` lookup maps in sync with the new label.
+    pub fn set_menu_item_label(&mut self, idx: u32, label: &str) -> Result<(), SystrayError> {
+        if let Err(e) = self.window.set_menu_item_label(idx, label) {
+            return Err(e);
+        }
+
+        if let Some(old_label) = self.items_reversed.remove(&idx) {
+            self.items.remove(&old_label);
+        }
+        self.items.insert(label.to_string(), idx);
+        self.items_reversed.insert(idx, label.to_string());
+
+        Ok(())
+    }
+
+    /// Enable or grey out a menu item without removing it.
+    pub fn set_menu_item_enabled(&mut self, idx: u32, enabled: bool) -> Result<(), SystrayError> {
+        self.window.set_menu_item_enabled(idx, enabled)
+    }
+
     pub fn add_menu_separator(&mut self) -> Result<u32, SystrayError> {
+        self.add_menu_separator_in(None)
+    }
+
+    fn add_menu_separator_in(&mut self, parent: Option<u32>) -> Result<u32, SystrayError> {
         let idx = self.menu_idx;
-        if let Err(e) = self.window.add_menu_separator(idx) {
+        if let Err(e) = self.window.add_menu_separator(parent, idx) {
             return Err(e);
         }
         self.menu_idx += 1;
         Ok(idx)
     }
 
+    /// Add a submenu, returning a handle that can be used to add items,
+    /// separators, and further nested submenus underneath it.
+    pub fn add_submenu(&mut self, label: &str) -> Result<SubMenu, SystrayError> {
+        self.add_submenu_in(None, label)
+    }
+
+    fn add_submenu_in(&mut self, parent: Option<u32>, label: &str) -> Result<SubMenu, SystrayError> {
+        let idx = self.menu_idx;
+        if let Err(e) = self.window.add_submenu_entry(parent, idx, label) {
+            return Err(e);
+        }
+
+        self.items.insert(label.to_string(), idx);
+        self.items_reversed.insert(idx, label.to_string());
+        self.menu_idx += 1;
+
+        Ok(SubMenu { idx: idx })
+    }
+
     pub fn set_icon_from_file(&self, file: &String) -> Result<(), SystrayError> {
         self.window.set_icon_from_file(file)
     }
@@ -144,6 +367,56 @@ impl Application {
         self.window.quit()
     }
 
+    /// Dispatch a single `SystrayEvent`, firing the matching menu-item or
+    /// tray-icon callback. Returns the selected item's name when `msg` was
+    /// a `MenuItem` event with a registered callback.
+    fn dispatch_event(&mut self, msg: SystrayEvent) -> Option<String> {
+        match msg {
+            SystrayEvent::MenuItem { index } => {
+                self.clear_radio_siblings(index);
+                if self.callback.contains_key(&index) {
+                    // TODO: Why are we removing from the HashSet every
+                    // time we want to use a callback?
+                    let f = self.callback.remove(&index).unwrap();
+                    f(self);
+                    self.callback.insert(index, f);
+
+                    return self.items_reversed.get(&index).cloned();
+                }
+                None
+            }
+            SystrayEvent::LeftClick => {
+                if let Some(f) = self.on_click.take() {
+                    f(self);
+                    self.on_click = Some(f);
+                }
+                None
+            }
+            SystrayEvent::DoubleClick => {
+                if let Some(f) = self.on_double_click.take() {
+                    f(self);
+                    self.on_double_click = Some(f);
+                }
+                None
+            }
+            SystrayEvent::RightClick => None,
+        }
+    }
+
+    /// Poll for a single event without blocking. Dispatches the matching
+    /// callback (if any) immediately and returns the event that was seen,
+    /// or `None` if nothing is waiting right now.
+    pub fn try_recv_message(&mut self) -> Option<SystrayEvent> {
+        match self.rx.try_recv() {
+            Ok(msg) => {
+                self.dispatch_event(msg);
+                Some(msg)
+            }
+            Err(_) => None,
+        }
+    }
+
+    #[cfg(not(feature = "async"))]
     pub fn wait_for_message(&mut self) {
         loop {
             let msg;
@@ -154,16 +427,13 @@ impl Application {
                     break;
                 }
             }
-            if self.callback.contains_key(&msg.menu_index) {
-                let f = self.callback.remove(&msg.menu_index).unwrap();
-                f(self);
-                self.callback.insert(msg.menu_index, f);
-            }
+            self.dispatch_event(msg);
         }
     }
 
     /// Wait for message and transmit the app object to the
     /// given callback
+    #[cfg(not(feature = "async"))]
     pub fn wait_for_message_callback<F>(&mut self, mut f: F)
     where
         F: FnMut(&mut Self, String)
@@ -178,14 +448,45 @@ impl Application {
                 }
             }
 
-            if self.callback.contains_key(&msg.menu_index) {
-                // TODO: Why are we removing from the HashSet every
-                // time we want to use a callback? 
-                let cb = self.callback.remove(&msg.menu_index).unwrap();
-                cb(self);
-                self.callback.insert(msg.menu_index, cb);
-                
-                let item_name = self.items_reversed.get(&msg.menu_index).unwrap().clone();
+            if let Some(item_name) = self.dispatch_event(msg) {
+                f(self, item_name);
+            }
+        }
+    }
+
+    /// Async equivalent of `wait_for_message`, for callers driving the
+    /// tray off a tokio runtime instead of a dedicated blocking thread.
+    #[cfg(feature = "async")]
+    pub async fn wait_for_message(&mut self) {
+        loop {
+            match self.rx.recv().await {
+                Some(msg) => {
+                    self.dispatch_event(msg);
+                }
+                None => {
+                    self.quit();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Async equivalent of `wait_for_message_callback`.
+    #[cfg(feature = "async")]
+    pub async fn wait_for_message_callback<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Self, String)
+    {
+        loop {
+            let msg = match self.rx.recv().await {
+                Some(m) => m,
+                None => {
+                    self.quit();
+                    break;
+                }
+            };
+
+            if let Some(item_name) = self.dispatch_event(msg) {
                 f(self, item_name);
             }
         }
@@ -197,3 +498,55 @@ impl Drop for Application {
         self.shutdown().ok();
     }
 }
+
+// `Application` itself isn't constructible in these tests: `Application::new`
+// always goes through `api::api::Window::new`, which makes real GTK/Win32
+// calls and needs a live OS loop. So these cover the logic that's genuinely
+// backend-independent: `SystrayError`'s trait impls, and the radio-group
+// membership lookup backing `clear_radio_siblings`/`dispatch_event`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn systray_error_display_formats_each_variant() {
+        assert_eq!(format!("{}", SystrayError::OsError("disk full".to_string())), "OsError: disk full");
+        assert_eq!(format!("{}", SystrayError::NotImplementedError), "Functionality is not implemented yet");
+        assert_eq!(format!("{}", SystrayError::UnknownError), "Unknown error occurrred");
+    }
+
+    #[test]
+    fn systray_error_from_boxed_error_forwards_display_and_source() {
+        let boxed: BoxedError = Box::new(std::io::Error::new(std::io::ErrorKind::Other, "broken pipe"));
+        let err: SystrayError = boxed.into();
+
+        assert_eq!(format!("{}", err), "broken pipe");
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn systray_error_source_is_none_without_a_wrapped_error() {
+        use std::error::Error;
+        assert!(SystrayError::OsError("x".to_string()).source().is_none());
+        assert!(SystrayError::NotImplementedError.source().is_none());
+        assert!(SystrayError::UnknownError.source().is_none());
+    }
+
+    #[test]
+    fn radio_group_members_includes_idx_and_its_group_only() {
+        let mut groups = HashMap::new();
+        groups.insert(1, 100);
+        groups.insert(2, 100);
+        groups.insert(3, 200);
+
+        let mut members = radio_group_members(&groups, 1);
+        members.sort();
+        assert_eq!(members, vec![1, 2]);
+    }
+
+    #[test]
+    fn radio_group_members_empty_when_idx_has_no_group() {
+        let groups: HashMap<u32, u32> = HashMap::new();
+        assert!(radio_group_members(&groups, 42).is_empty());
+    }
+}